@@ -1,14 +1,30 @@
-use std::{collections::HashMap, str};
+use std::{
+    collections::HashMap,
+    fs,
+    num::NonZeroUsize,
+    str,
+    sync::{Arc as StdArc, Mutex as StdMutex},
+    time::{Duration, Instant},
+};
 
 use anyhow::{bail, Context, Result};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
 use base64::{engine::general_purpose, Engine};
 use clap::Parser;
 use lazy_static::lazy_static;
-use ldap3::{LdapConn, Scope};
+use ldap3::exop::PasswordModify;
+use ldap3::{Ldap, LdapConnAsync, LdapConnSettings, ResultEntry, Scope};
 use ldap3::SearchEntry;
 use log::{debug, info};
+use lru::LruCache;
 use regex::Regex;
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore};
 use tide::{Request, Response, StatusCode};
+use tokio::sync::Mutex;
+use zeroize::Zeroizing;
 
 /// (username, password)
 type UserInfo<'a> = (&'a str, &'a str);
@@ -23,6 +39,15 @@ const LDAP_HEADERS: &[&str] = &[
     "X-Ldap-BindPass",
     "X-Ldap-Template",
 ];
+/// Headers that further tune a request but are not required for every deployment
+const OPTIONAL_LDAP_HEADERS: &[&str] = &[
+    "X-Ldap-RequireGroup",
+    "X-Ldap-GroupBaseDN",
+    "X-Ldap-StartTLS",
+    "X-Ldap-ReturnAttrs",
+];
+/// Separator joining the values of a multi-valued attribute in its `X-Auth-*` response header
+const ATTR_VALUE_SEPARATOR: &str = ",";
 
 #[derive(Parser, Debug)]
 pub struct Cli {
@@ -35,6 +60,397 @@ pub struct Cli {
     /// The endpoint the authentication service should respond on
     #[arg(long, default_value = "/auth-proxy")]
     pub auth_endpoint: String,
+    /// The endpoint the password-change service should respond on
+    #[arg(long, default_value = "/passwd-proxy")]
+    pub passwd_endpoint: String,
+    /// Maximum number of idle search-phase connections kept per LDAP URL/bind DN in the pool
+    #[arg(long, default_value = "10")]
+    pub pool_size: usize,
+    /// Seconds a pooled search-phase connection may sit idle before it is discarded instead of reused
+    #[arg(long, default_value = "300")]
+    pub pool_idle_timeout: u64,
+    /// Seconds a successful authentication is cached before it must be re-verified against LDAP
+    #[arg(long, default_value = "60")]
+    pub cache_ttl: u64,
+    /// Maximum number of cached credential entries kept before the least-recently-used is evicted
+    #[arg(long, default_value = "1000")]
+    pub cache_max_entries: usize,
+    /// Path to a CA certificate used to validate the LDAP server's TLS certificate
+    #[arg(long)]
+    pub tls_ca_cert: Option<String>,
+    /// Path to a client certificate presented for mutual TLS
+    #[arg(long)]
+    pub tls_client_cert: Option<String>,
+    /// Path to the private key matching `--tls-client-cert`
+    #[arg(long)]
+    pub tls_client_key: Option<String>,
+    /// Skip verifying the LDAP server's TLS certificate (insecure, for self-signed test environments)
+    #[arg(long)]
+    pub tls_insecure_skip_verify: bool,
+}
+
+/// TLS settings derived from the `Cli` flags, applied to every outbound LDAP connection
+#[derive(Clone, Default)]
+pub struct TlsConfig {
+    ca_cert_path: Option<String>,
+    client_cert_path: Option<String>,
+    client_key_path: Option<String>,
+    insecure_skip_verify: bool,
+}
+
+impl TlsConfig {
+    pub fn from_cli(cli: &Cli) -> Self {
+        Self {
+            ca_cert_path: cli.tls_ca_cert.clone(),
+            client_cert_path: cli.tls_client_cert.clone(),
+            client_key_path: cli.tls_client_key.clone(),
+            insecure_skip_verify: cli.tls_insecure_skip_verify,
+        }
+    }
+
+    /// Builds the rustls client config this deployment should use for `ldaps://` and StartTLS
+    /// connections
+    fn client_config(&self) -> Result<ClientConfig> {
+        let builder = ClientConfig::builder().with_safe_defaults();
+
+        if self.insecure_skip_verify {
+            return Ok(builder
+                .with_custom_certificate_verifier(StdArc::new(NoCertVerification))
+                .with_no_client_auth());
+        }
+
+        let mut roots = RootCertStore::empty();
+        if let Some(ca_cert_path) = &self.ca_cert_path {
+            let pem = fs::read(ca_cert_path).context("Could not read CA certificate")?;
+            for cert in rustls_pemfile::certs(&mut pem.as_slice())
+                .context("Could not parse CA certificate")?
+            {
+                roots
+                    .add(&Certificate(cert))
+                    .context("Invalid CA certificate")?;
+            }
+        } else {
+            roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+                rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    ta.subject,
+                    ta.spki,
+                    ta.name_constraints,
+                )
+            }));
+        }
+        let builder = builder.with_root_certificates(roots);
+
+        match (&self.client_cert_path, &self.client_key_path) {
+            (Some(cert_path), Some(key_path)) => {
+                let cert_chain = rustls_pemfile::certs(&mut fs::read(cert_path)?.as_slice())
+                    .context("Could not parse client certificate")?
+                    .into_iter()
+                    .map(Certificate)
+                    .collect();
+                let key_pem = fs::read(key_path)?;
+                let key = rustls_pemfile::pkcs8_private_keys(&mut key_pem.as_slice())
+                    .context("Could not parse client private key")?
+                    .into_iter()
+                    .next()
+                    .or(rustls_pemfile::rsa_private_keys(&mut key_pem.as_slice())
+                        .context("Could not parse client private key")?
+                        .into_iter()
+                        .next())
+                    .or(rustls_pemfile::ec_private_keys(&mut key_pem.as_slice())
+                        .context("Could not parse client private key")?
+                        .into_iter()
+                        .next())
+                    .map(PrivateKey)
+                    .context("No PKCS#8, RSA or SEC1 private key found in client key file")?;
+                Ok(builder
+                    .with_client_auth_cert(cert_chain, key)
+                    .context("Invalid client certificate/key pair")?)
+            }
+            _ => Ok(builder.with_no_client_auth()),
+        }
+    }
+}
+
+struct NoCertVerification;
+
+impl rustls::client::ServerCertVerifier for NoCertVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
+
+/// Opens an LDAP connection, upgrading to TLS for `ldaps://` URLs or when `starttls` is set
+///
+/// Authentication fails closed: if TLS negotiation fails, the `Err` propagates all the way back
+/// to `auth_get` instead of silently falling back to a plaintext connection.
+async fn connect(url: &str, starttls: bool, tls: &TlsConfig) -> Result<(LdapConnAsync, Ldap)> {
+    let is_ldaps = url.starts_with("ldaps://");
+    if !is_ldaps && !starttls {
+        return Ok(LdapConnAsync::new(url).await?);
+    }
+
+    let config = tls.client_config().context("Could not build TLS configuration")?;
+    let mut settings = LdapConnSettings::new().set_config(StdArc::new(config));
+    if starttls && !is_ldaps {
+        settings = settings.set_starttls(true);
+    }
+
+    LdapConnAsync::with_settings(settings, url)
+        .await
+        .context("TLS negotiation with the LDAP server failed")
+}
+
+/// Reads the `X-Ldap-StartTLS` header and reports whether a plain `ldap://` connection should be
+/// upgraded via the StartTLS extended operation before binding
+fn want_starttls(ldap_options: &HashMap<String, &str>) -> bool {
+    matches!(
+        ldap_options.get("X-Ldap-StartTLS").copied(),
+        Some("true") | Some("1")
+    )
+}
+
+/// Identifies a bucket of pooled search-phase connections: the LDAP URL, the service bind DN
+/// they were bound with, and whether they were upgraded via StartTLS
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct PoolKey {
+    url: String,
+    bind_dn: String,
+    starttls: bool,
+}
+
+struct PooledConn {
+    ldap: Ldap,
+    idle_since: Instant,
+}
+
+/// A bounded pool of search-phase LDAP connections, keyed by LDAP URL and service bind DN
+///
+/// Each `auth_get` request binds with the service account to perform the user search; without
+/// pooling this means a fresh TCP connection and bind for every request nginx proxies through
+/// `auth_request`. The pool hands out a reused, already-bound [`Ldap`] handle when one is
+/// available and not past `idle_timeout`, and otherwise opens a new connection. The per-user
+/// verification bind never goes through this pool: it always uses its own short-lived connection.
+pub struct LdapPool {
+    max_size: usize,
+    idle_timeout: Duration,
+    tls: TlsConfig,
+    conns: Mutex<HashMap<PoolKey, Vec<PooledConn>>>,
+}
+
+impl LdapPool {
+    pub fn new(max_size: usize, idle_timeout: Duration, tls: TlsConfig) -> Self {
+        Self {
+            max_size,
+            idle_timeout,
+            tls,
+            conns: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns a connection bound as `bind_dn` (or anonymous, if empty) against `url`, and whether
+    /// it is a reused pooled connection rather than a freshly-opened one
+    ///
+    /// Reuses a pooled connection for the same `(url, bind_dn, starttls)` tuple when one is
+    /// available and still fresh, otherwise opens and binds a new one. A reused connection is not
+    /// liveness-checked: the server or an intermediate firewall may have silently reaped its TCP
+    /// session well within `idle_timeout`, so callers should retry once against a fresh connection
+    /// (via [`Self::get_fresh`]) if their first operation on a reused handle fails.
+    async fn get(&self, url: &str, bind_dn: &str, bind_pass: &str, starttls: bool) -> Result<(Ldap, bool)> {
+        let key = PoolKey {
+            url: url.to_string(),
+            bind_dn: bind_dn.to_string(),
+            starttls,
+        };
+
+        {
+            let mut conns = self.conns.lock().await;
+            if let Some(bucket) = conns.get_mut(&key) {
+                while let Some(pooled) = bucket.pop() {
+                    if pooled.idle_since.elapsed() < self.idle_timeout {
+                        debug!("Reusing pooled connection for '{}'", url);
+                        return Ok((pooled.ldap, true));
+                    }
+                }
+            }
+        }
+
+        Ok((self.get_fresh(url, bind_dn, bind_pass, starttls).await?, false))
+    }
+
+    /// Opens and binds a brand new connection as `bind_dn` against `url`, bypassing the pool
+    async fn get_fresh(&self, url: &str, bind_dn: &str, bind_pass: &str, starttls: bool) -> Result<Ldap> {
+        debug!("Opening new pooled connection to '{}'", url);
+        let (conn, mut ldap) = connect(url, starttls, &self.tls).await?;
+        tokio::spawn(async move {
+            if let Err(e) = conn.drive().await {
+                debug!("Pooled ldap connection driver exited: {}", e);
+            }
+        });
+
+        if !bind_dn.is_empty() {
+            ldap.simple_bind(bind_dn, bind_pass).await?.success()?;
+        }
+
+        Ok(ldap)
+    }
+
+    /// Returns a connection to the pool for reuse, dropping it instead if the bucket is full
+    async fn put(&self, url: &str, bind_dn: &str, starttls: bool, ldap: Ldap) {
+        if self.max_size == 0 {
+            return;
+        }
+
+        let key = PoolKey {
+            url: url.to_string(),
+            bind_dn: bind_dn.to_string(),
+            starttls,
+        };
+        let mut conns = self.conns.lock().await;
+        let bucket = conns.entry(key).or_default();
+        if bucket.len() < self.max_size {
+            bucket.push(PooledConn {
+                ldap,
+                idle_since: Instant::now(),
+            });
+        }
+    }
+}
+
+struct CacheEntry {
+    password_hash: String,
+    auth_result: AuthResult,
+    expires_at: Instant,
+}
+
+/// Caches successful authentications so repeated `auth_request` checks for the same credentials
+/// don't hit the LDAP server on every HTTP request
+///
+/// Entries are keyed by username plus every LDAP option that affects the outcome of
+/// [`query_ldap`] (URL, template, base DN, service bind DN, required group, group base DN and
+/// requested attributes), and
+/// store only an Argon2 salted hash of the password, never the plaintext, so a leak of the cache
+/// contents does not disclose credentials. Entries expire after `ttl` and the cache evicts the
+/// least-recently-used entry once `max_entries` is reached.
+pub struct CredentialCache {
+    ttl: Duration,
+    entries: StdMutex<LruCache<String, CacheEntry>>,
+}
+
+impl CredentialCache {
+    pub fn new(max_entries: usize, ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: StdMutex::new(LruCache::new(
+                NonZeroUsize::new(max_entries).unwrap_or(NonZeroUsize::new(1).unwrap()),
+            )),
+        }
+    }
+
+    /// Builds the cache key for `username`
+    ///
+    /// This must include every option that can change what [`query_ldap`] returns or whether it
+    /// succeeds at all. Besides the URL and template used to resolve the user's DN, that also
+    /// covers the base DN the search runs under (two locations can share a template but scope it
+    /// under different base DNs), the service bind DN (which can change what attributes/groups a
+    /// search is even allowed to see), and the group/attribute options — otherwise a cached result
+    /// for one `auth_request` location would be served to a different location that scopes the
+    /// search differently, or sets `X-Ldap-RequireGroup`/`X-Ldap-ReturnAttrs`, without ever
+    /// re-checking them.
+    fn cache_key(username: &str, ldap_options: &HashMap<String, &str>) -> String {
+        let get = |header: &str| ldap_options.get(header).copied().unwrap_or_default();
+        format!(
+            "{}\0{}\0{}\0{}\0{}\0{}\0{}\0{}",
+            username,
+            get("X-Ldap-URL"),
+            get("X-Ldap-Template"),
+            get("X-Ldap-BaseDN"),
+            get("X-Ldap-BindDN"),
+            get("X-Ldap-RequireGroup"),
+            get("X-Ldap-GroupBaseDN"),
+            get("X-Ldap-ReturnAttrs"),
+        )
+    }
+
+    /// Returns the cached auth result if `password` matches a still-fresh cached entry for
+    /// `username`
+    pub fn verify(
+        &self,
+        username: &str,
+        password: &str,
+        ldap_options: &HashMap<String, &str>,
+    ) -> Option<AuthResult> {
+        let key = Self::cache_key(username, ldap_options);
+        let password = Zeroizing::new(password.to_string());
+
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get(&key)?;
+        if Instant::now() >= entry.expires_at {
+            entries.pop(&key);
+            return None;
+        }
+
+        let parsed_hash = PasswordHash::new(&entry.password_hash).ok()?;
+        // `verify_password` compares the computed hash in constant time.
+        Argon2::default()
+            .verify_password(password.as_bytes(), &parsed_hash)
+            .ok()?;
+
+        Some(AuthResult {
+            groups: entry.auth_result.groups.clone(),
+            attrs: entry.auth_result.attrs.clone(),
+        })
+    }
+
+    /// Stores a salted Argon2 hash of `password` and the matched `auth_result` for `username`,
+    /// refreshing any existing entry
+    pub fn insert(
+        &self,
+        username: &str,
+        password: &str,
+        ldap_options: &HashMap<String, &str>,
+        auth_result: AuthResult,
+    ) {
+        let key = Self::cache_key(username, ldap_options);
+        let password = Zeroizing::new(password.to_string());
+
+        let salt = SaltString::generate(&mut OsRng);
+        let password_hash = match Argon2::default().hash_password(password.as_bytes(), &salt) {
+            Ok(hash) => hash.to_string(),
+            Err(e) => {
+                debug!("Could not hash password for credential cache entry: {}", e);
+                return;
+            }
+        };
+
+        self.entries.lock().unwrap().put(
+            key,
+            CacheEntry {
+                password_hash,
+                auth_result,
+                expires_at: Instant::now() + self.ttl,
+            },
+        );
+    }
+}
+
+/// Builds the `X-Auth-*` response header name for a requested LDAP attribute, e.g. `mail` becomes
+/// `X-Auth-Mail` and `displayName` becomes `X-Auth-DisplayName`
+pub fn attr_header_name(attr: &str) -> String {
+    let mut name = String::from("X-Auth-");
+    let mut chars = attr.chars();
+    if let Some(first) = chars.next() {
+        name.push(first.to_ascii_uppercase());
+    }
+    name.push_str(chars.as_str());
+    name
 }
 
 /// Returns a unauthorized Response
@@ -82,7 +498,9 @@ pub fn get_userdata_from_authorization(header: &str) -> Result<(String, String)>
 }
 
 /// Returns the necessary options for querying the LDAP server from the request headers
-pub fn get_ldap_options_from_headers(req: &Request<()>) -> Result<HashMap<String, &str>> {
+pub fn get_ldap_options_from_headers<State: Clone + Send + Sync + 'static>(
+    req: &Request<State>,
+) -> Result<HashMap<String, &str>> {
     debug!("Extracting ldap options from request headers");
     let mut header_map: HashMap<String, &str> = HashMap::new();
     for header in LDAP_HEADERS {
@@ -93,52 +511,328 @@ pub fn get_ldap_options_from_headers(req: &Request<()>) -> Result<HashMap<String
             None => bail!("{} header is missing", header),
         };
     }
+    for header in OPTIONAL_LDAP_HEADERS {
+        let value = req.header(*header).map(|h| h.last().as_str()).unwrap_or("");
+        header_map.insert(header.to_string(), value);
+    }
     Ok(header_map)
 }
 
+/// The outcome of a successful [`query_ldap`] call
+#[derive(Debug, Default, Clone)]
+pub struct AuthResult {
+    /// Names (`cn`) of the groups the user was found to be a member of, when
+    /// `X-Ldap-GroupBaseDN` was set
+    pub groups: Vec<String>,
+    /// The attributes requested via `X-Ldap-ReturnAttrs`, present when found on the user's entry.
+    /// Multi-valued attributes are joined with [`ATTR_VALUE_SEPARATOR`].
+    pub attrs: HashMap<String, String>,
+}
+
 /// Queries the LDAP server for the given username and checks for a correct password
 ///
+/// If `X-Ldap-BindDN` is set, the search phase is performed over a pooled connection bound as
+/// that service account, since most directories (Active Directory, locked-down OpenLDAP) reject
+/// anonymous search. If it is empty, the search falls back to an anonymous connection, which is
+/// pooled separately from service-bound ones.
+///
+/// As a shortcut, when `X-Ldap-Template` is a DN pattern instead of a search filter (i.e. it
+/// does not start with `(`), the search phase is skipped entirely and the user is bound against
+/// directly, saving a round trip.
+///
+/// When `X-Ldap-GroupBaseDN` is set, the user's group memberships are looked up after a
+/// successful bind. If `X-Ldap-RequireGroup` is also set and the user is not a member of that
+/// group, authentication fails even though the password was correct.
+///
 /// # Returns
 ///
 /// This function returns [`Ok`] if the filter was successful. When the filter did not find any
 /// result or the ldap server responded with an error (e.g. invalid password), an [`Err`] is returned
-pub fn query_ldap(
-    (username, password): UserInfo,
+pub async fn query_ldap(
+    (username, password): UserInfo<'_>,
     ldap_options: HashMap<String, &str>,
-) -> Result<()> {
-    debug!("Starting ldap connection");
-    let mut ldap = LdapConn::new(ldap_options.get("X-Ldap-URL").unwrap())?;
+    pool: &LdapPool,
+) -> Result<AuthResult> {
+    let url = ldap_options.get("X-Ldap-URL").unwrap();
+    let template = ldap_options.get("X-Ldap-Template").unwrap();
+    let service = ServiceBind {
+        url,
+        bind_dn: ldap_options.get("X-Ldap-BindDN").copied().unwrap_or(""),
+        bind_pass: ldap_options.get("X-Ldap-BindPass").copied().unwrap_or(""),
+        starttls: want_starttls(&ldap_options),
+    };
+
+    let user_dn = resolve_user_dn(
+        pool,
+        &service,
+        template,
+        ldap_options.get("X-Ldap-BaseDN").unwrap(),
+        username,
+    )
+    .await?;
+
+    bind_as_user(url, &user_dn, username, password, service.starttls, &pool.tls).await?;
+
+    let group_base_dn = ldap_options
+        .get("X-Ldap-GroupBaseDN")
+        .copied()
+        .unwrap_or("");
+    let require_group = ldap_options
+        .get("X-Ldap-RequireGroup")
+        .copied()
+        .unwrap_or("");
+    let groups = if group_base_dn.is_empty() {
+        if !require_group.is_empty() {
+            bail!(
+                "X-Ldap-RequireGroup is set to '{}' but X-Ldap-GroupBaseDN is empty",
+                require_group
+            );
+        }
+        Vec::new()
+    } else {
+        let groups = find_groups(pool, &service, group_base_dn, &user_dn).await?;
+
+        if !require_group.is_empty() && !groups.iter().any(|g| g == require_group) {
+            bail!(
+                "User '{}' is not a member of required group '{}'",
+                username,
+                require_group
+            );
+        }
+
+        groups
+    };
+
+    let return_attrs: Vec<&str> = ldap_options
+        .get("X-Ldap-ReturnAttrs")
+        .copied()
+        .unwrap_or("")
+        .split(',')
+        .map(str::trim)
+        .filter(|attr| !attr.is_empty())
+        .collect();
+    let attrs = if return_attrs.is_empty() {
+        HashMap::new()
+    } else {
+        fetch_attrs(pool, &service, &user_dn, &return_attrs).await?
+    };
+
+    Ok(AuthResult { groups, attrs })
+}
+
+/// The service-account connection parameters shared by every lookup `query_ldap` and
+/// `change_password` perform against the directory, bundled to keep those functions' argument
+/// lists within reason
+struct ServiceBind<'a> {
+    url: &'a str,
+    bind_dn: &'a str,
+    bind_pass: &'a str,
+    starttls: bool,
+}
+
+/// Runs a search over a pooled connection bound per `service`, retrying once against a
+/// freshly-opened connection if a reused pooled handle's first attempt fails
+///
+/// Pooled TCP sessions can be silently reaped by the server or an intermediate firewall well
+/// within the pool's idle timeout; without this retry, the first search on such a connection
+/// would otherwise surface as a spurious auth failure for an nginx `auth_request` caller.
+async fn pooled_search(
+    pool: &LdapPool,
+    service: &ServiceBind<'_>,
+    base: &str,
+    scope: Scope,
+    filter: &str,
+    attrs: Vec<&str>,
+) -> Result<Vec<ResultEntry>> {
+    let (mut ldap, reused) = pool
+        .get(service.url, service.bind_dn, service.bind_pass, service.starttls)
+        .await?;
+
+    let search_result = ldap
+        .search(base, scope, filter, attrs.clone())
+        .await
+        .and_then(|res| res.success());
+
+    let (rs, _res) = match search_result {
+        Ok(rs) => rs,
+        Err(e) if reused => {
+            debug!(
+                "Reused pooled connection to '{}' failed ({}), retrying on a fresh connection",
+                service.url, e
+            );
+            let mut ldap = pool
+                .get_fresh(service.url, service.bind_dn, service.bind_pass, service.starttls)
+                .await?;
+            let (rs, _res) = ldap
+                .search(base, scope, filter, attrs)
+                .await
+                .and_then(|res| res.success())?;
+            pool.put(service.url, service.bind_dn, service.starttls, ldap)
+                .await;
+            return Ok(rs);
+        }
+        Err(e) => {
+            // The connection may be in a bad state; don't return it to the pool.
+            return Err(e.into());
+        }
+    };
+
+    pool.put(service.url, service.bind_dn, service.starttls, ldap)
+        .await;
+
+    Ok(rs)
+}
+
+/// Resolves `username` to its distinguished name
+///
+/// When `template` is a DN pattern rather than a search filter, this is a pure string
+/// substitution with no LDAP round trip. Otherwise it searches `base_dn` with the filter built
+/// from `template`, over a pooled connection bound as `service.bind_dn`.
+async fn resolve_user_dn(
+    pool: &LdapPool,
+    service: &ServiceBind<'_>,
+    template: &str,
+    base_dn: &str,
+    username: &str,
+) -> Result<String> {
+    if !template.trim_start().starts_with('(') {
+        debug!("Template is a DN pattern, resolving '{}' directly", username);
+        return Ok(template.replace("%(username)s", username));
+    }
 
     // Prepare searchfilter
-    let filter = ldap_options
-        .get("X-Ldap-Template")
-        .unwrap()
-        .replace("%(username)s", username);
+    let filter = template.replace("%(username)s", username);
 
     debug!("Querying with filter {:?}", &filter);
-    let (rs, _res) = ldap
-        .search(
-            ldap_options.get("X-Ldap-BaseDN").unwrap(),
-            Scope::Subtree,
-            &filter,
-            Vec::<&str>::new(),
-        )?
-        .success()?;
-
-    debug!("Closing ldap connection");
-    ldap.unbind()?;
+    let rs = pooled_search(pool, service, base_dn, Scope::Subtree, &filter, Vec::new()).await?;
 
     if rs.is_empty() {
         bail!("User not found with given filter");
     }
 
-    let user_dn = SearchEntry::construct(rs.first().unwrap().clone()).dn;
+    Ok(SearchEntry::construct(rs.first().unwrap().clone()).dn)
+}
+
+/// Escapes a value for safe interpolation into an LDAP search filter, per
+/// [RFC 4515](https://datatracker.ietf.org/doc/html/rfc4515#section-3)
+///
+/// `user_dn` is built from client-controlled input (the `Authorization` header username), so it
+/// must never be interpolated into a filter unescaped.
+fn escape_filter_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '*' => escaped.push_str("\\2a"),
+            '(' => escaped.push_str("\\28"),
+            ')' => escaped.push_str("\\29"),
+            '\\' => escaped.push_str("\\5c"),
+            '\0' => escaped.push_str("\\00"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Looks up the `cn` of every group under `group_base_dn` that lists `user_dn` as a member
+///
+/// Matches the `member` and `uniqueMember` style of group membership, the conventions lldap and
+/// most directories use.
+async fn find_groups(
+    pool: &LdapPool,
+    service: &ServiceBind<'_>,
+    group_base_dn: &str,
+    user_dn: &str,
+) -> Result<Vec<String>> {
+    debug!("Looking up group membership for '{}'", user_dn);
+
+    let user_dn = escape_filter_value(user_dn);
+    let filter = format!(
+        "(|(member={user_dn})(uniqueMember={user_dn}))",
+        user_dn = user_dn
+    );
+    let rs = pooled_search(
+        pool,
+        service,
+        group_base_dn,
+        Scope::Subtree,
+        &filter,
+        vec!["cn"],
+    )
+    .await?;
+
+    Ok(rs
+        .into_iter()
+        .map(SearchEntry::construct)
+        .filter_map(|entry| entry.attrs.get("cn").and_then(|v| v.first()).cloned())
+        .collect())
+}
+
+/// Retrieves `attrs` from the user's own entry, joining multi-valued attributes with
+/// [`ATTR_VALUE_SEPARATOR`]; attributes absent on the entry are simply not present in the result
+async fn fetch_attrs(
+    pool: &LdapPool,
+    service: &ServiceBind<'_>,
+    user_dn: &str,
+    attrs: &[&str],
+) -> Result<HashMap<String, String>> {
+    debug!("Fetching attributes {:?} for '{}'", attrs, user_dn);
+    let rs = pooled_search(
+        pool,
+        service,
+        user_dn,
+        Scope::Base,
+        "(objectClass=*)",
+        attrs.to_vec(),
+    )
+    .await?;
+
+    let entry = match rs.into_iter().next() {
+        Some(entry) => SearchEntry::construct(entry),
+        None => return Ok(HashMap::new()),
+    };
+
+    Ok(attrs
+        .iter()
+        .filter_map(|attr| {
+            entry
+                .attrs
+                .get(*attr)
+                .filter(|values| !values.is_empty())
+                .map(|values| (attr.to_string(), values.join(ATTR_VALUE_SEPARATOR)))
+        })
+        .collect())
+}
+
+/// Opens a fresh, unpooled connection and verifies `password` by binding as `user_dn`
+///
+/// This is the per-user verification step: it always runs on its own short-lived connection so
+/// the service-account connection used for the search is never exposed to untrusted credentials.
+async fn bind_as_user(
+    url: &str,
+    user_dn: &str,
+    username: &str,
+    password: &str,
+    starttls: bool,
+    tls: &TlsConfig,
+) -> Result<()> {
+    if password.is_empty() {
+        // An empty password performs an LDAP unauthenticated bind, which many directories answer
+        // with success, so a blank password would otherwise authenticate as any valid user.
+        bail!("Password invalid");
+    }
+
     debug!("Checking if the password of user '{}' is correct", username);
-    let mut ldap = LdapConn::new(ldap_options.get("X-Ldap-URL").unwrap())?;
-    let ldap_bind = ldap.simple_bind(&user_dn, password);
+    let (conn, mut ldap) = connect(url, starttls, tls).await?;
+    tokio::spawn(async move {
+        if let Err(e) = conn.drive().await {
+            debug!("Verification ldap connection driver exited: {}", e);
+        }
+    });
+
+    let ldap_bind = ldap.simple_bind(user_dn, password).await;
 
     if ldap_bind.is_err() {
-        ldap.unbind()?;
+        ldap.unbind().await?;
         debug!("Password for user is invalid");
         bail!("Password invalid");
     }
@@ -147,13 +841,80 @@ pub fn query_ldap(
         bail!("Password invalid");
     }
 
-    ldap.unbind()?;
+    ldap.unbind().await?;
 
     info!("Auth data for user {} correctly", username);
 
     Ok(())
 }
 
+/// Changes a user's own directory password via the RFC 3062 Password Modify Extended Operation
+///
+/// The current password is verified with a normal bind first, and the password-modify extended
+/// request is then issued over that same connection, so the directory never sees the new
+/// password without having already confirmed the old one.
+pub async fn change_password(
+    (username, old_password): UserInfo<'_>,
+    new_password: &str,
+    ldap_options: HashMap<String, &str>,
+    pool: &LdapPool,
+) -> Result<()> {
+    let url = ldap_options.get("X-Ldap-URL").unwrap();
+    let template = ldap_options.get("X-Ldap-Template").unwrap();
+    let service = ServiceBind {
+        url,
+        bind_dn: ldap_options.get("X-Ldap-BindDN").copied().unwrap_or(""),
+        bind_pass: ldap_options.get("X-Ldap-BindPass").copied().unwrap_or(""),
+        starttls: want_starttls(&ldap_options),
+    };
+
+    let user_dn = resolve_user_dn(
+        pool,
+        &service,
+        template,
+        ldap_options.get("X-Ldap-BaseDN").unwrap(),
+        username,
+    )
+    .await?;
+
+    let old_password = Zeroizing::new(old_password.to_string());
+    let new_password = Zeroizing::new(new_password.to_string());
+
+    debug!("Verifying current password for '{}' before changing it", username);
+    let (conn, mut ldap) = connect(url, service.starttls, &pool.tls).await?;
+    tokio::spawn(async move {
+        if let Err(e) = conn.drive().await {
+            debug!("Password-change ldap connection driver exited: {}", e);
+        }
+    });
+
+    let bind_result = ldap
+        .simple_bind(&user_dn, &old_password)
+        .await
+        .and_then(|res| res.success());
+    if bind_result.is_err() {
+        let _ = ldap.unbind().await;
+        bail!("Current password is invalid");
+    }
+
+    debug!("Issuing password modify extended operation for '{}'", username);
+    let modify_result = ldap
+        .extended(PasswordModify {
+            user_id: None,
+            old_pass: Some(&old_password),
+            new_pass: Some(&new_password),
+        })
+        .await
+        .and_then(|res| res.success());
+
+    ldap.unbind().await?;
+    modify_result.context("LDAP server rejected the new password")?;
+
+    info!("Password for user {} changed successfully", username);
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -177,4 +938,82 @@ mod test {
         assert!(validate_auth_header(Some(missing_basic)).is_err());
         assert!(validate_auth_header(missing_header).is_err());
     }
+
+    #[test]
+    fn test_attr_header_name() {
+        assert_eq!(attr_header_name("mail"), "X-Auth-Mail");
+        assert_eq!(attr_header_name("displayName"), "X-Auth-DisplayName");
+        assert_eq!(attr_header_name(""), "X-Auth-");
+    }
+
+    fn test_ldap_options() -> HashMap<String, &'static str> {
+        HashMap::from([
+            ("X-Ldap-URL".to_string(), "ldap://localhost"),
+            ("X-Ldap-Template".to_string(), "cn=%(username)s,dc=example,dc=com"),
+        ])
+    }
+
+    #[test]
+    fn test_credential_cache_round_trip() {
+        let cache = CredentialCache::new(10, Duration::from_secs(60));
+        let ldap_options = test_ldap_options();
+        let auth_result = AuthResult {
+            groups: vec!["admins".to_string()],
+            attrs: HashMap::new(),
+        };
+
+        assert!(cache.verify("mkapra", "test123", &ldap_options).is_none());
+
+        cache.insert("mkapra", "test123", &ldap_options, auth_result.clone());
+
+        let cached = cache.verify("mkapra", "test123", &ldap_options).unwrap();
+        assert_eq!(cached.groups, auth_result.groups);
+    }
+
+    #[test]
+    fn test_credential_cache_wrong_password() {
+        let cache = CredentialCache::new(10, Duration::from_secs(60));
+        let ldap_options = test_ldap_options();
+
+        cache.insert("mkapra", "test123", &ldap_options, AuthResult::default());
+
+        assert!(cache.verify("mkapra", "wrong", &ldap_options).is_none());
+    }
+
+    #[test]
+    fn test_credential_cache_expiry() {
+        let cache = CredentialCache::new(10, Duration::from_millis(0));
+        let ldap_options = test_ldap_options();
+
+        cache.insert("mkapra", "test123", &ldap_options, AuthResult::default());
+
+        assert!(cache.verify("mkapra", "test123", &ldap_options).is_none());
+    }
+
+    #[test]
+    fn test_credential_cache_key_scoped_to_group_options() {
+        let cache = CredentialCache::new(10, Duration::from_secs(60));
+        let mut ldap_options = test_ldap_options();
+
+        cache.insert("mkapra", "test123", &ldap_options, AuthResult::default());
+
+        ldap_options.insert("X-Ldap-RequireGroup".to_string(), "admins");
+        assert!(cache.verify("mkapra", "test123", &ldap_options).is_none());
+    }
+
+    #[test]
+    fn test_credential_cache_key_scoped_to_base_and_bind_dn() {
+        let cache = CredentialCache::new(10, Duration::from_secs(60));
+        let mut ldap_options = test_ldap_options();
+
+        cache.insert("mkapra", "test123", &ldap_options, AuthResult::default());
+
+        ldap_options.insert("X-Ldap-BaseDN".to_string(), "ou=other,dc=example,dc=com");
+        assert!(cache.verify("mkapra", "test123", &ldap_options).is_none());
+
+        let mut ldap_options = test_ldap_options();
+        cache.insert("mkapra", "test123", &ldap_options, AuthResult::default());
+        ldap_options.insert("X-Ldap-BindDN".to_string(), "cn=other,dc=example,dc=com");
+        assert!(cache.verify("mkapra", "test123", &ldap_options).is_none());
+    }
 }
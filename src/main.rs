@@ -1,13 +1,22 @@
+use std::{sync::Arc, time::Duration};
+
 use anyhow::Result;
 use clap::Parser;
 use tide::{Request, StatusCode};
 
 use ldap_authd::{
-    get_ldap_options_from_headers, get_userdata_from_authorization, query_ldap,
-    unauthorized_response, validate_auth_header, Cli,
+    attr_header_name, change_password, get_ldap_options_from_headers,
+    get_userdata_from_authorization, query_ldap, unauthorized_response, validate_auth_header,
+    AuthResult, Cli, CredentialCache, LdapPool, TlsConfig,
 };
 
-async fn auth_get(req: Request<()>) -> tide::Result {
+#[derive(Clone)]
+struct AppState {
+    pool: Arc<LdapPool>,
+    cache: Arc<CredentialCache>,
+}
+
+async fn auth_get(req: Request<AppState>) -> tide::Result {
     let auth_header = req.header("Authorization").map(|h| h.as_str());
     if validate_auth_header(auth_header).is_err() {
         return Ok(unauthorized_response());
@@ -22,12 +31,70 @@ async fn auth_get(req: Request<()>) -> tide::Result {
     }
     let ldap_options = ldap_options.unwrap();
 
-    if query_ldap((username, password), ldap_options).is_err() {
-        // User is not in queried group
+    let cache = req.state().cache.clone();
+    if let Some(auth_result) = cache.verify(username, password, &ldap_options) {
+        return Ok(ok_response(&auth_result));
+    }
+
+    let pool = req.state().pool.clone();
+    let auth_result = match query_ldap((username, password), ldap_options.clone(), &pool).await {
+        Ok(auth_result) => auth_result,
+        Err(_) => {
+            // Invalid password or user is not in a required group
+            return Ok(unauthorized_response());
+        }
+    };
+    cache.insert(username, password, &ldap_options, auth_result.clone());
+
+    Ok(ok_response(&auth_result))
+}
+
+/// Builds the `200` response for a successful authentication, forwarding matched group names and
+/// any requested attributes so nginx can expose them to the protected backend
+fn ok_response(auth_result: &AuthResult) -> tide::Response {
+    let mut response = tide::Response::builder(StatusCode::Ok)
+        .header("X-Auth-Groups", auth_result.groups.join(","));
+    for (attr, value) in &auth_result.attrs {
+        response = response.header(attr_header_name(attr).as_str(), value.as_str());
+    }
+    response.build()
+}
+
+async fn passwd_post(mut req: Request<AppState>) -> tide::Result {
+    let auth_header = req.header("Authorization").map(|h| h.as_str());
+    if validate_auth_header(auth_header).is_err() {
         return Ok(unauthorized_response());
     }
+    let auth_header = auth_header.unwrap();
+
+    let (username, old_password) = &get_userdata_from_authorization(auth_header)?;
+
+    // Read the new password before borrowing `req` again for its headers: `body_string` needs a
+    // mutable borrow, which can't coexist with the header borrows `ldap_options` holds.
+    let new_password = match req.header("X-Ldap-NewPassword") {
+        Some(h) => h.last().as_str().to_string(),
+        None => req.body_string().await.unwrap_or_default(),
+    };
+    if new_password.is_empty() {
+        return Ok(tide::Response::builder(StatusCode::BadRequest)
+            .body("Missing new password")
+            .build());
+    }
 
-    Ok(StatusCode::Ok.into())
+    let ldap_options = get_ldap_options_from_headers(&req);
+    if ldap_options.is_err() {
+        // Should not happen if the configuration of the nginx server is correct
+        return Ok(StatusCode::BadRequest.into());
+    }
+    let ldap_options = ldap_options.unwrap();
+
+    let pool = req.state().pool.clone();
+    match change_password((username, old_password), &new_password, ldap_options, &pool).await {
+        Ok(()) => Ok(StatusCode::Ok.into()),
+        Err(e) => Ok(tide::Response::builder(StatusCode::Unauthorized)
+            .body(e.to_string())
+            .build()),
+    }
 }
 
 #[tokio::main]
@@ -36,9 +103,22 @@ async fn main() -> Result<()> {
 
     tide::log::with_level(tide::log::LevelFilter::Debug);
 
-    let mut app = tide::new();
+    let state = AppState {
+        pool: Arc::new(LdapPool::new(
+            args.pool_size,
+            Duration::from_secs(args.pool_idle_timeout),
+            TlsConfig::from_cli(&args),
+        )),
+        cache: Arc::new(CredentialCache::new(
+            args.cache_max_entries,
+            Duration::from_secs(args.cache_ttl),
+        )),
+    };
+
+    let mut app = tide::with_state(state);
     app.with(tide::log::LogMiddleware::new());
     app.at(&args.auth_endpoint).get(auth_get);
+    app.at(&args.passwd_endpoint).post(passwd_post);
     app.listen(format!("{}:{}", args.hostname, args.port))
         .await?;
     Ok(())